@@ -1,34 +1,98 @@
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self};
 use std::sync::{Arc, Mutex};
 use std::thread::{self};
+use std::time::Duration;
 use std::{fs, usize};
 
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Head,
+    Options,
+}
+
+impl Method {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw.to_ascii_uppercase().as_str() {
+            "GET" => Some(Method::Get),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "PATCH" => Some(Method::Patch),
+            "HEAD" => Some(Method::Head),
+            "OPTIONS" => Some(Method::Options),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct HttpRequest {
-    method: String,
+    method: Method,
     path: String,
     version: String,
     headers: HashMap<String, String>,
-    body: String,
+    query: HashMap<String, String>,
+    cookies: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum RequestError {
+    Malformed,
+    HeadersTooLarge,
+    PayloadTooLarge,
+    Io(io::Error),
+}
+
+impl From<io::Error> for RequestError {
+    fn from(err: io::Error) -> Self {
+        RequestError::Io(err)
+    }
 }
 
 impl HttpRequest {
-    fn from_raw(request: &str) -> Self {
-        let mut lines = request.lines();
-        let mut headers = HashMap::new();
-        let mut body = String::new();
+    fn read_from(stream: &TcpStream) -> Result<Self, RequestError> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        let mut reader = BufReader::new(stream);
+
+        let request_line = read_bounded_line(&mut reader, MAX_HEADER_BYTES)?;
 
-        let request_line = lines.next().unwrap_or_default();
         let mut parts = request_line.split_whitespace();
-        let method = parts.next().unwrap_or("").to_string();
-        let path = parts.next().unwrap_or("").to_string();
-        let version = parts.next().unwrap_or("").to_string();
+        let method = parts.next().ok_or(RequestError::Malformed)?;
+        let method = Method::parse(method).ok_or(RequestError::Malformed)?;
+        let target = parts.next().ok_or(RequestError::Malformed)?;
+        let version = parts.next().ok_or(RequestError::Malformed)?.to_string();
 
-        for line in lines.by_ref() {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query_string)) => (path.to_string(), parse_query_string(query_string)),
+            None => (target.to_string(), HashMap::new()),
+        };
+
+        let mut headers = HashMap::new();
+        let mut header_budget = MAX_HEADER_BYTES.saturating_sub(request_line.len());
+        loop {
+            if header_budget == 0 {
+                return Err(RequestError::HeadersTooLarge);
+            }
+            let line = read_bounded_line(&mut reader, header_budget)?;
+            header_budget = header_budget.saturating_sub(line.len());
+
+            let line = line.trim_end_matches(['\r', '\n']);
             if line.is_empty() {
                 break;
             }
@@ -37,22 +101,213 @@ impl HttpRequest {
             }
         }
 
-        body = lines.collect::<Vec<&str>>().join("\n");
+        let cookies = headers
+            .get("Cookie")
+            .map(|raw| parse_cookie_header(raw))
+            .unwrap_or_default();
+
+        let content_length = headers
+            .get("Content-Length")
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        if content_length > MAX_BODY_SIZE {
+            return Err(RequestError::PayloadTooLarge);
+        }
+
+        let mut body = vec![0; content_length];
+        if content_length > 0 {
+            reader.read_exact(&mut body)?;
+        }
 
-        HttpRequest {
+        Ok(HttpRequest {
             method,
             path,
             version,
             headers,
+            query,
+            cookies,
+            body,
+        })
+    }
+}
+
+/// Reads one line (including its trailing `\n`, if any) but never buffers more
+/// than `limit` bytes for it. If the limit is hit before a newline shows up,
+/// that's a too-large request line/header rather than a truncated one.
+fn read_bounded_line(reader: &mut BufReader<&TcpStream>, limit: usize) -> Result<String, RequestError> {
+    let mut line = String::new();
+    let bytes_read = reader.by_ref().take(limit as u64).read_line(&mut line)?;
+
+    if bytes_read > 0 && bytes_read as u64 >= limit as u64 && !line.ends_with('\n') {
+        return Err(RequestError::HeadersTooLarge);
+    }
+
+    Ok(line)
+}
+
+fn parse_query_string(raw: &str) -> HashMap<String, String> {
+    let mut query = HashMap::new();
+    for pair in raw.split('&') {
+        if pair.is_empty() {
+            continue;
+        }
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        query.insert(percent_decode(key), percent_decode(value));
+    }
+    query
+}
+
+fn parse_cookie_header(raw: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for pair in raw.split("; ") {
+        if let Some((key, value)) = pair.split_once('=') {
+            cookies.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    cookies
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                // Both neighbours are ASCII hex digits, so this is a valid
+                // one-byte slice regardless of what comes after it in `input`.
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[derive(Debug, Clone)]
+struct Response {
+    status: u16,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            headers: HashMap::new(),
             body,
         }
     }
+
+    fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    fn json(status: u16, body: &str) -> Self {
+        Response::new(status, body.as_bytes().to_vec()).with_header("Content-Type", "application/json")
+    }
+
+    fn html(status: u16, body: &str) -> Self {
+        Response::new(status, body.as_bytes().to_vec()).with_header("Content-Type", "text/html")
+    }
+
+    fn file(body: Vec<u8>, content_type: &str) -> Self {
+        Response::new(200, body).with_header("Content-Type", content_type)
+    }
+
+    fn not_found() -> Self {
+        Response::html(404, "<h1>404 - Page Not Found </h1>")
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut header = format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+            self.status,
+            status_text(self.status),
+            self.body.len()
+        );
+        for (key, value) in &self.headers {
+            header.push_str(&format!("{key}: {value}\r\n"));
+        }
+        header.push_str("\r\n");
+
+        let mut response = header.into_bytes();
+        response.extend_from_slice(&self.body);
+        response
+    }
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+type RouteHandler = Box<dyn Fn(&HttpRequest) -> Response + Send + Sync>;
+
+#[derive(Default)]
+struct Router {
+    routes: HashMap<(Method, String), RouteHandler>,
+    wildcard_routes: Vec<(Method, String, RouteHandler)>,
+}
+
+impl Router {
+    fn new() -> Self {
+        Router::default()
+    }
+
+    fn route<F>(&mut self, method: Method, path: &str, handler: F)
+    where
+        F: Fn(&HttpRequest) -> Response + Send + Sync + 'static,
+    {
+        match path.strip_suffix("/*") {
+            Some(prefix) => self
+                .wildcard_routes
+                .push((method, format!("{prefix}/"), Box::new(handler))),
+            None => {
+                self.routes.insert((method, path.to_string()), Box::new(handler));
+            }
+        }
+    }
+
+    fn handle(&self, request: &HttpRequest) -> Option<Response> {
+        if let Some(handler) = self.routes.get(&(request.method, request.path.clone())) {
+            return Some(handler(request));
+        }
+
+        self.wildcard_routes
+            .iter()
+            .find(|(method, prefix, _)| *method == request.method && request.path.starts_with(prefix.as_str()))
+            .map(|(_, _, handler)| handler(request))
+    }
 }
 
 #[derive(Debug)]
 struct ThreadPool {
     workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    sender: mpsc::Sender<Message>,
 }
 
 impl ThreadPool {
@@ -72,7 +327,24 @@ impl ThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.sender.send(Box::new(job)).unwrap();
+        self.sender.send(Message::NewJob(Box::new(job))).unwrap();
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        for _ in &self.workers {
+            self.sender.send(Message::Terminate).unwrap();
+        }
+
+        for worker in &mut self.workers {
+            println!("Shutting down worker {}", worker._id);
+            if let Some(thread) = worker.thread.take() {
+                if let Err(panic) = thread.join() {
+                    println!("Worker {} panicked: {:?}", worker._id, panic);
+                }
+            }
+        }
     }
 }
 
@@ -83,14 +355,18 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
         let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv();
-            match job {
-                Ok(job) => {
+            let message = receiver.lock().unwrap().recv();
+            match message {
+                Ok(Message::NewJob(job)) => {
                     println!("Worker {id} executing job ...");
                     job();
                 }
+                Ok(Message::Terminate) => {
+                    println!("Worker {id} received terminate signal.");
+                    break;
+                }
                 Err(_) => break,
             }
         });
@@ -104,41 +380,74 @@ impl Worker {
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+enum Message {
+    NewJob(Job),
+    Terminate,
+}
+
 fn main() {
     let listener = TcpListener::bind("127.0.0.1:3000").expect("Failed to bind port");
+    listener
+        .set_nonblocking(true)
+        .expect("Failed to set listener to non-blocking mode");
+
     let num_threads = num_cpus::get();
     let pool = ThreadPool::new(num_threads);
 
+    let mut router = Router::new();
+    router.route(Method::Get, "/api/hello", |request| {
+        let name = request.query.get("name").map(String::as_str).unwrap_or("Api");
+        Response::json(200, &format!(r#"{{"message":"Hello ,{name}"}}"#))
+    });
+    router.route(Method::Get, "/api/session", |request| match request.cookies.get("session") {
+        Some(session) => Response::json(200, &format!(r#"{{"session":"{session}"}}"#)),
+        None => Response::json(400, r#"{"error":"missing session cookie"}"#),
+    });
+    router.route(Method::Post, "/api/echo", |request| {
+        let body = String::from_utf8_lossy(&request.body);
+        Response::json(200, &format!(r#"{{"body":{body:?}}}"#))
+    });
+    let router = Arc::new(router);
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let handler_shutdown = Arc::clone(&shutdown);
+    ctrlc::set_handler(move || {
+        handler_shutdown.store(true, Ordering::SeqCst);
+    })
+    .expect("Failed to set Ctrl+C handler");
+
     println!("Server is runing on http://127.0.0.1:3000 with {num_threads} threads");
 
     for stream in listener.incoming() {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+
         match stream {
             Ok(stream) => {
-                pool.execute(|| handle_client(stream));
+                let router = Arc::clone(&router);
+                pool.execute(move || handle_client(stream, router));
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(std::time::Duration::from_millis(100));
             }
             Err(e) => {
                 println!("Connection failed: {}", e);
             }
         }
     }
+
+    println!("Shutting down.");
+    drop(pool);
 }
 
-fn handle_api_request(request: &HttpRequest) -> String {
-    match request.path.as_str() {
-        "/api/hello" => {
-            format_response("200 Ok", r#"{"message":"Hello ,Api"}"#, "application/json")
-        }
-        _ => format_response(
-            "404 Not Found",
-            r#"{"error":"Not found"}"#,
-            "application/json",
-        ),
+fn handle_request(request: &HttpRequest, router: &Router) -> Response {
+    if let Some(response) = router.handle(request) {
+        return response;
     }
-}
 
-fn handle_request(request: &HttpRequest) -> String {
     if request.path.starts_with("/api/") {
-        return handle_api_request(request);
+        return Response::json(404, r#"{"error":"Not found"}"#);
     }
 
     let mut file_path = format!("public{}", request.path);
@@ -147,35 +456,48 @@ fn handle_request(request: &HttpRequest) -> String {
     }
 
     if Path::new(&file_path).exists() {
-        if let Ok(contents) = fs::read_to_string(&file_path) {
-            return format_response("200 Ok", &contents, "text/html");
+        if let Ok(contents) = fs::read(&file_path) {
+            let content_type = content_type_for(&file_path);
+            return Response::file(contents, content_type);
         }
     }
 
-    format_response(
-        "404 Not Found",
-        "<h1>404 - Page Not Found </h1>",
-        "text/htlm",
-    )
+    Response::not_found()
 }
 
-fn format_response(status: &str, body: &str, content_type: &str) -> String {
-    format!(
-        "HTTP/1.1 {status}\r\nContent-Length: {}\r\nContent-Type: {}\r\n\r\n{body}",
-        body.len(),
-        content_type
-    )
+fn content_type_for(file_path: &str) -> &'static str {
+    match Path::new(file_path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+    {
+        Some("html") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("txt") => "text/plain",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
 }
 
-fn handle_client(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    if let Ok(bytes_read) = stream.read(&mut buffer) {
-        let request_str = String::from_utf8_lossy(&buffer[..bytes_read]);
-        let request = HttpRequest::from_raw(&request_str);
+fn handle_client(mut stream: TcpStream, router: Arc<Router>) {
+    let response = match HttpRequest::read_from(&stream) {
+        Ok(request) => handle_request(&request, &router),
+        Err(RequestError::PayloadTooLarge) | Err(RequestError::HeadersTooLarge) => {
+            Response::html(413, "<h1>413 - Payload Too Large</h1>")
+        }
+        Err(RequestError::Io(err)) => {
+            println!("Failed to read request: {err}");
+            Response::html(400, "<h1>400 - Bad Request</h1>")
+        }
+        Err(RequestError::Malformed) => Response::html(400, "<h1>400 - Bad Request</h1>"),
+    };
 
-        // Define a simple HTTP Response
-        let response = handle_request(&request);
-        stream.write_all(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
-    }
+    stream.write_all(&response.into_bytes()).unwrap();
+    stream.flush().unwrap();
 }